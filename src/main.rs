@@ -15,11 +15,14 @@ use std::io;
 use std::io::Stdin;
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io::IoSlice;
 use std::io::Read;
+use std::io::Write;
 use std::path::Path;
 use std::thread;
 use std::result::Result as StdResult;
 use std::cmp::max;
+use std::convert::TryInto;
 
 use anyhow::anyhow;
 use anyhow::Context as ErrContext;
@@ -31,7 +34,7 @@ use clap::CommandFactory;
 use crossbeam::channel::bounded;
 use crossbeam::channel::Sender;
 use crossbeam::channel::Receiver;
-use crossbeam::queue::ArrayQueue;
+use crossbeam::channel::TryRecvError;
 
 use smashquote::unescape_bytes;
 
@@ -75,6 +78,14 @@ struct Args {
     /// Line delimiter
     #[clap(short='d', long, parse(from_os_str), default_value = "\n")]
     delimiter: OsString,
+
+    /// Limit how many records are forwarded
+    ///
+    /// A positive N forwards only the first N delimited records and then
+    /// closes the pipe. A negative N forwards only the last N records,
+    /// which requires buffering the tail of the stream until EOF.
+    #[clap(short='n', long, allow_hyphen_values = true)]
+    lines: Option<i64>,
 }
 
 impl Args {
@@ -125,44 +136,323 @@ enum Message {
     End(Result<()>),
 }
 
+/// Word-at-a-time search for a single delimiter byte, in the style of
+/// `memchr`: load 8 bytes at once, XOR against the byte broadcast across a
+/// `u64`, and use the classic `(v - 0x0101..01) & !v & 0x8080..80` trick to
+/// detect a zero byte in the XOR result.
+fn find_byte(haystack: &[u8], needle: u8) -> Option<usize> {
+    const LO: u64 = 0x0101_0101_0101_0101;
+    const HI: u64 = 0x8080_8080_8080_8080;
+    let broadcast = LO * (needle as u64);
+    let mut chunks = haystack.chunks_exact(8);
+    let mut offset = 0usize;
+    for chunk in &mut chunks {
+        let v = u64::from_ne_bytes(chunk.try_into().expect("chunk is exactly 8 bytes"));
+        let x = v ^ broadcast;
+        if x.wrapping_sub(LO) & !x & HI != 0 {
+            for (i, &b) in chunk.iter().enumerate() {
+                if b == needle {
+                    return Some(offset + i);
+                }
+            }
+        }
+        offset += 8;
+    }
+    for (i, &b) in chunks.remainder().iter().enumerate() {
+        if b == needle {
+            return Some(offset + i);
+        }
+    }
+    None
+}
+
+/// Boyer-Moore-Horspool search for a multi-byte delimiter: a 256-entry bad
+/// character skip table lets us jump past mismatches instead of re-scanning
+/// byte by byte.
+fn find_bytes_horspool(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let n = needle.len();
+    if haystack.len() < n {
+        return None;
+    }
+    let mut skip = [n; 256];
+    for (i, &b) in needle[..n - 1].iter().enumerate() {
+        skip[b as usize] = n - 1 - i;
+    }
+    let last = n - 1;
+    let mut pos = 0usize;
+    while pos + n <= haystack.len() {
+        let mut i = last;
+        while haystack[pos + i] == needle[i] {
+            if i == 0 {
+                return Some(pos);
+            }
+            i -= 1;
+        }
+        pos += skip[haystack[pos + last] as usize];
+    }
+    None
+}
+
 fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
-    for offset in 0usize..(haystack.len()-needle.len()) {
-        if &haystack[offset..(offset+needle.len())] == needle {
-            return Some(offset);
+    match needle.len() {
+        0 => if haystack.is_empty() { None } else { Some(0) },
+        1 => find_byte(haystack, needle[0]),
+        _ => find_bytes_horspool(haystack, needle),
+    }
+}
+
+#[cfg(test)]
+mod find_bytes_tests {
+    use super::find_bytes;
+
+    #[test]
+    fn single_byte_delimiter() {
+        assert_eq!(find_bytes(b"abc\ndef", b"\n"), Some(3));
+        assert_eq!(find_bytes(b"abcdef", b"\n"), None);
+    }
+
+    #[test]
+    fn single_byte_spans_a_word_boundary() {
+        // The match sits past the first 8-byte word, which exercises the
+        // chunked scan's offset bookkeeping.
+        let haystack = b"0123456789\n";
+        assert_eq!(find_bytes(haystack, b"\n"), Some(10));
+    }
+
+    #[test]
+    fn multi_byte_delimiter() {
+        assert_eq!(find_bytes(b"one\r\ntwo\r\n", b"\r\n"), Some(3));
+        assert_eq!(find_bytes(b"one<->two", b"<->"), Some(3));
+        assert_eq!(find_bytes(b"onetwo", b"<->"), None);
+    }
+
+    #[test]
+    fn needle_straddling_the_tail_of_the_haystack() {
+        // Regression: the delimiter's leading bytes appear right at the end
+        // of a read, as if it straddled a read-boundary chunk.
+        assert_eq!(find_bytes(b"abc<-", b"<->"), None);
+        assert_eq!(find_bytes(b"abc<->", b"<->"), Some(3));
+    }
+
+    #[test]
+    fn needle_longer_than_haystack_does_not_panic() {
+        assert_eq!(find_bytes(b"ab", b"abcd"), None);
+        assert_eq!(find_bytes(b"", b"x"), None);
+    }
+
+    #[test]
+    fn last_valid_offset_is_included() {
+        assert_eq!(find_bytes(b"ab", b"b"), Some(1));
+        assert_eq!(find_bytes(b"abcd", b"cd"), Some(2));
+    }
+}
+
+/// Read from `cur_file`, transparently moving on to the next queued file
+/// once the current one hits EOF, so the caller sees one continuous stream.
+/// Only returns `Ok(0)` once every file (including `cur_file`) is drained.
+fn read_any(
+    cur_file: &mut InFileish,
+    in_files: &mut VecDeque<InFileish>,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    loop {
+        match cur_file.read(buf)? {
+            0 => match in_files.pop_front() {
+                Some(next) => { *cur_file = next; }
+                None => return Ok(0),
+            },
+            bytes => return Ok(bytes),
+        }
+    }
+}
+
+/// How the reader disposes of each completed record, driven by `-n/--lines`.
+enum LineMode {
+    /// Forward every record as it completes.
+    Stream,
+    /// Forward this many more records, then close the pipe.
+    Head(usize),
+    /// Hold only the most recent `cap` records, forwarding them at EOF.
+    Tail { ring: VecDeque<Vec<u8>>, cap: usize },
+}
+
+impl LineMode {
+    fn new(lines: Option<i64>) -> LineMode {
+        match lines {
+            None => LineMode::Stream,
+            Some(n) if n >= 0 => LineMode::Head(n as usize),
+            Some(n) => LineMode::Tail { ring: VecDeque::new(), cap: (-n) as usize },
+        }
+    }
+}
+
+/// Route one completed record according to `mode`. Returns `Ok(true)` once
+/// the caller should stop reading entirely: head mode has hit its line
+/// count and has already sent `Message::End` down `full_sender` itself.
+fn emit_line(
+    mode: &mut LineMode,
+    local_pool: &mut Vec<Vec<u8>>,
+    full_sender: &Sender<Message>,
+    line: Vec<u8>,
+) -> Result<bool> {
+    match mode {
+        LineMode::Stream => {
+            full_sender.send(Message::Line(line)).with_context(||
+                format!("Bug: Main thread hung up on reader (while sending).")
+            )?;
+            Ok(false)
+        }
+        LineMode::Head(remaining) => {
+            full_sender.send(Message::Line(line)).with_context(||
+                format!("Bug: Main thread hung up on reader (while sending).")
+            )?;
+            *remaining -= 1;
+            if *remaining == 0 {
+                full_sender.send(Message::End(Ok(()))).with_context(||
+                    format!("Bug: Main thread hung up on reader (while sending EOF).")
+                )?;
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        }
+        LineMode::Tail { ring, cap } => {
+            // The ring holds its own freshly-allocated copy rather than the
+            // pool-borrowed `line` itself: `line` came out of the shared
+            // buffer pool (sized to `--buffer-lines`), and tail mode forwards
+            // nothing through `full_sender` until EOF, so if the ring kept
+            // borrowing from that pool a large `-n -N` would starve it and
+            // deadlock the reader/writer against each other. Recycling `line`
+            // immediately instead keeps the ring's depth independent of
+            // `--buffer-lines`.
+            ring.push_back(line.clone());
+            if ring.len() > *cap {
+                ring.pop_front();
+            }
+            let mut recycled = line;
+            recycled.clear();
+            local_pool.push(recycled);
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod emit_line_tests {
+    use super::emit_line;
+    use super::LineMode;
+    use super::Message;
+    use crossbeam::channel::bounded;
+
+    fn recv_line(receiver: &crossbeam::channel::Receiver<Message>) -> Vec<u8> {
+        match receiver.recv().expect("a message is waiting") {
+            Message::Line(line) => line,
+            Message::End(_) => panic!("expected a line, got End"),
+        }
+    }
+
+    #[test]
+    fn stream_forwards_every_line_immediately() {
+        let mut mode = LineMode::Stream;
+        let mut local_pool = Vec::new();
+        let (sender, receiver) = bounded(4);
+        assert_eq!(emit_line(&mut mode, &mut local_pool, &sender, b"a".to_vec()).unwrap(), false);
+        assert_eq!(emit_line(&mut mode, &mut local_pool, &sender, b"b".to_vec()).unwrap(), false);
+        assert_eq!(recv_line(&receiver), b"a");
+        assert_eq!(recv_line(&receiver), b"b");
+    }
+
+    #[test]
+    fn head_stops_after_n_lines_and_sends_end() {
+        let mut mode = LineMode::Head(2);
+        let mut local_pool = Vec::new();
+        let (sender, receiver) = bounded(4);
+        assert_eq!(emit_line(&mut mode, &mut local_pool, &sender, b"a".to_vec()).unwrap(), false);
+        assert_eq!(emit_line(&mut mode, &mut local_pool, &sender, b"b".to_vec()).unwrap(), true);
+        assert_eq!(recv_line(&receiver), b"a");
+        assert_eq!(recv_line(&receiver), b"b");
+        assert!(matches!(receiver.recv().unwrap(), Message::End(Ok(()))));
+    }
+
+    #[test]
+    fn tail_keeps_only_the_last_cap_lines_and_forwards_nothing_until_eof() {
+        let mut mode = LineMode::Tail { ring: Default::default(), cap: 2 };
+        let mut local_pool = Vec::new();
+        let (sender, receiver) = bounded(4);
+        for line in [b"a".to_vec(), b"b".to_vec(), b"c".to_vec()] {
+            assert_eq!(emit_line(&mut mode, &mut local_pool, &sender, line).unwrap(), false);
+        }
+        assert!(receiver.try_recv().is_err(), "tail mode must not forward before EOF");
+        match &mode {
+            LineMode::Tail { ring, .. } => {
+                assert_eq!(ring.iter().cloned().collect::<Vec<_>>(), vec![b"b".to_vec(), b"c".to_vec()]);
+            }
+            _ => panic!("expected Tail mode"),
+        }
+    }
+
+    #[test]
+    fn tail_recycles_the_pool_buffer_instead_of_parking_it_in_the_ring() {
+        // Regression: the ring used to hold the very Vec<u8> drawn from the
+        // shared buffer pool, so a cap close to --buffer-lines starved that
+        // pool and deadlocked the reader/writer. The ring must hold its own
+        // copy and hand the original buffer back to local_pool right away.
+        let mut mode = LineMode::Tail { ring: Default::default(), cap: 1 };
+        let mut local_pool = Vec::new();
+        let (sender, _receiver) = bounded(4);
+        emit_line(&mut mode, &mut local_pool, &sender, b"hello".to_vec()).unwrap();
+        assert_eq!(local_pool.len(), 1);
+        assert!(local_pool[0].is_empty(), "the recycled buffer should be cleared");
+        match &mode {
+            LineMode::Tail { ring, .. } => assert_eq!(ring.front().unwrap(), b"hello"),
+            _ => panic!("expected Tail mode"),
         }
     }
-    return None;
 }
 
 fn reader(
     args: &'static Args,
     full_sender: Sender<Message>,
     empty_receiver: Receiver<Message>,
+    mut in_files: VecDeque<InFileish>,
     delimiter: Vec<u8>,
 ) -> Result<()> {
-    let stdin_h = std::io::stdin();
-    let mut si = stdin_h.lock();
+    let mut cur_file: InFileish = in_files.pop_front()
+        .unwrap_or_else(|| InFileish::S(io::stdin()));
     const PAGE: usize = 4096;
     let mut hold_buf: Option<Vec<u8>> = None;
+    let mut mode = LineMode::new(args.lines);
+    let mut local_pool: Vec<Vec<u8>> = Vec::new();
+    if let LineMode::Head(0) = mode {
+        full_sender.send(Message::End(Ok(()))).with_context(||
+            format!("Bug: Main thread hung up on reader (while sending EOF).")
+        )?;
+        return Ok(());
+    }
     loop {
-        let message: Message = match empty_receiver.recv() {
-            StdResult::Ok(m) => m,
-            StdResult::Err(e) => { 
-                return Result::Err(e).with_context(||
-                    format!("Bug: Main thread hung up on reader.")
-                );
-            }
-        };
-        let mut next_buf: Vec<u8> = match message {
-            Message::Line(v) => v,
-            Message::End(res) => match res {
-                Ok(_) => {
-                    return Err(anyhow!("Main thread asked us to stop reading."));
-                }
-                Err(e) => {
-                    return Err(e).with_context(||
-                        format!("Bug: Recieved error from main thread.")
-                    );
+        let mut next_buf: Vec<u8> = match local_pool.pop() {
+            Some(buf) => buf,
+            None => {
+                let message: Message = match empty_receiver.recv() {
+                    StdResult::Ok(m) => m,
+                    StdResult::Err(e) => {
+                        return Result::Err(e).with_context(||
+                            format!("Bug: Main thread hung up on reader.")
+                        );
+                    }
+                };
+                match message {
+                    Message::Line(v) => v,
+                    Message::End(res) => match res {
+                        Ok(_) => {
+                            return Err(anyhow!("Main thread asked us to stop reading."));
+                        }
+                        Err(e) => {
+                            return Err(e).with_context(||
+                                format!("Bug: Recieved error from main thread.")
+                            );
+                        }
+                    }
                 }
             }
         };
@@ -174,9 +464,9 @@ fn reader(
                     let leftover = delimiter_off + delimiter.len();
                     next_buf.extend_from_slice(&held[leftover..]);
                     held.truncate(leftover);
-                    full_sender.send(Message::Line(held)).with_context(||
-                        format!("Bug: Main thread hung up on reader (while sending again).")
-                    )?;
+                    if emit_line(&mut mode, &mut local_pool, &full_sender, held)? {
+                        return Ok(());
+                    }
                     hold_buf = Some(next_buf);
                     continue;
                 }
@@ -185,13 +475,30 @@ fn reader(
                     #[allow(unused_mut)]
                     let mut next_size: usize = ((offset / PAGE) + 1) * PAGE;
                     held.resize(next_size, 0u8);
-                    match si.read(&mut held[offset..next_size]) {
+                    match read_any(&mut cur_file, &mut in_files, &mut held[offset..next_size]) {
                         Ok(0) => {
-                            // EOF
+                            // EOF on every input file
                             held.truncate(offset);
-                            full_sender.send(Message::Line(held)).with_context(||
-                                format!("Bug: Main thread hung up on reader (while sending last).")
-                            )?;
+                            match &mut mode {
+                                LineMode::Tail { ring, cap } => {
+                                    if !held.is_empty() {
+                                        ring.push_back(held);
+                                        if ring.len() > *cap {
+                                            ring.pop_front();
+                                        }
+                                    }
+                                    while let Some(line) = ring.pop_front() {
+                                        full_sender.send(Message::Line(line)).with_context(||
+                                            format!("Bug: Main thread hung up on reader (while sending tail buffer).")
+                                        )?;
+                                    }
+                                }
+                                _ => {
+                                    full_sender.send(Message::Line(held)).with_context(||
+                                        format!("Bug: Main thread hung up on reader (while sending last).")
+                                    )?;
+                                }
+                            }
                             full_sender.send(Message::End(Ok(()))).with_context(||
                                 format!("Bug: Main thread hung up on reader (while sending EOF).")
                             )?;
@@ -200,17 +507,17 @@ fn reader(
                         }
                         Ok(bytes) => {
                             held.truncate(offset+bytes);
-                            let search_start: usize = 
+                            let search_start: usize =
                                 max(0isize, (offset as isize)-(delimiter.len() as isize)+1isize)
                                 .try_into().expect("positive");
                             match find_bytes(&held[search_start..], &delimiter) {
                                 Some(delimiter_off) => {
-                                    let leftover = delimiter_off + delimiter.len();
+                                    let leftover = search_start + delimiter_off + delimiter.len();
                                     next_buf.extend_from_slice(&held[leftover..]);
                                     held.truncate(leftover);
-                                    full_sender.send(Message::Line(held)).with_context(||
-                                        format!("Bug: Main thread hung up on reader (while sending).")
-                                    )?;
+                                    if emit_line(&mut mode, &mut local_pool, &full_sender, held)? {
+                                        return Ok(());
+                                    }
                                     hold_buf = Some(next_buf);
                                     break;
                                 }
@@ -221,7 +528,7 @@ fn reader(
                         }
                         Err(e) => {
                             return Err(e).with_context(||
-                                format!("Error reading stdin")
+                                format!("Error reading input")
                             );
                         }
                     };
@@ -231,67 +538,303 @@ fn reader(
     }
 }
 
+/// The largest number of buffers we may hand to a single `write_vectored`
+/// call. POSIX caps `writev`'s iovec count, but glibc/musl only expose it
+/// via `sysconf(_SC_IOV_MAX)` rather than a compile-time constant, so we
+/// query it once at runtime and fall back to 1024 (the same cap every other
+/// platform we support already hardcodes) if the query fails.
+#[cfg(unix)]
+fn iov_max() -> usize {
+    use std::sync::OnceLock;
+    static IOV_MAX: OnceLock<usize> = OnceLock::new();
+    *IOV_MAX.get_or_init(|| {
+        let lim = unsafe { libc::sysconf(libc::_SC_IOV_MAX) };
+        if lim > 0 { lim as usize } else { 1024 }
+    })
+}
+#[cfg(not(unix))]
+fn iov_max() -> usize {
+    1024
+}
+
+/// Write `bufs` to `w` with as few syscalls as possible, using
+/// `write_vectored` and retrying around short/partial writes.
+///
+/// `write_vectored` is free to report a byte count that only covers some
+/// whole buffers plus part of the next one, so on retry we have to skip the
+/// buffers it fully consumed and resume the first partially-written one at
+/// the right offset. We also cap each call at `chunk_cap` buffers, since a
+/// `pending` batch built from a generous `--high-wm`/`--buffer-lines` can
+/// otherwise exceed what a single `writev` is allowed to accept.
+fn write_vectored_chunked<W: Write>(w: &mut W, bufs: &[Vec<u8>], chunk_cap: usize) -> io::Result<()> {
+    let mut start_idx = 0usize;
+    let mut start_off = 0usize;
+    while start_idx < bufs.len() {
+        let chunk_end = bufs.len().min(start_idx + chunk_cap);
+        let slices: Vec<IoSlice<'_>> = bufs[start_idx..chunk_end]
+            .iter()
+            .enumerate()
+            .map(|(i, buf)| {
+                if i == 0 {
+                    IoSlice::new(&buf[start_off..])
+                } else {
+                    IoSlice::new(buf)
+                }
+            })
+            .collect();
+        if slices.iter().all(|s| s.is_empty()) {
+            // Nothing to write in this chunk (e.g. a trailing empty
+            // record); no point issuing a syscall for it.
+            start_idx = chunk_end;
+            start_off = 0;
+            continue;
+        }
+        let mut written = w.write_vectored(&slices)?;
+        if written == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "failed to write whole buffer",
+            ));
+        }
+        drop(slices);
+        while written > 0 {
+            let cur_len = bufs[start_idx].len() - start_off;
+            if written < cur_len {
+                start_off += written;
+                written = 0;
+            } else {
+                written -= cur_len;
+                start_idx += 1;
+                start_off = 0;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write `bufs` to `w`, chunked at `iov_max()` buffers per `write_vectored`
+/// call. See [`write_vectored_chunked`] for the retry/chunking details.
+fn write_vectored_all<W: Write>(w: &mut W, bufs: &[Vec<u8>]) -> io::Result<()> {
+    write_vectored_chunked(w, bufs, iov_max())
+}
+
+#[cfg(test)]
+mod write_vectored_tests {
+    use super::write_vectored_chunked;
+    use std::io;
+    use std::io::IoSlice;
+    use std::io::Write;
+
+    /// A `Write` that only ever accepts `max_per_call` bytes per
+    /// `write_vectored` call, to exercise the partial-write retry path.
+    struct StingyWriter {
+        max_per_call: usize,
+        written: Vec<u8>,
+        call_lens: Vec<usize>,
+    }
+
+    impl Write for StingyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.call_lens.push(bufs.len());
+            let mut remaining = self.max_per_call;
+            let mut total = 0usize;
+            for buf in bufs {
+                if remaining == 0 {
+                    break;
+                }
+                let take = buf.len().min(remaining);
+                self.written.extend_from_slice(&buf[..take]);
+                total += take;
+                remaining -= take;
+                if take < buf.len() {
+                    break;
+                }
+            }
+            Ok(total)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn retries_around_partial_writes() {
+        let bufs = vec![b"abc".to_vec(), b"de".to_vec(), b"fghi".to_vec()];
+        let mut w = StingyWriter { max_per_call: 4, written: Vec::new(), call_lens: Vec::new() };
+        write_vectored_chunked(&mut w, &bufs, 1024).expect("write succeeds");
+        assert_eq!(w.written, b"abcdefghi");
+        assert!(w.call_lens.len() > 1, "a stingy writer should force more than one call");
+    }
+
+    #[test]
+    fn chunks_at_the_given_cap() {
+        let bufs: Vec<Vec<u8>> = (0..5).map(|i| vec![b'a' + i]).collect();
+        let mut w = StingyWriter { max_per_call: usize::MAX, written: Vec::new(), call_lens: Vec::new() };
+        write_vectored_chunked(&mut w, &bufs, 2).expect("write succeeds");
+        assert_eq!(w.written, b"abcde");
+        assert_eq!(w.call_lens, vec![2, 2, 1], "5 buffers chunked at 2 per call");
+    }
+
+    #[test]
+    fn skips_a_syscall_for_an_all_empty_batch() {
+        let bufs: Vec<Vec<u8>> = vec![Vec::new()];
+        let mut w = StingyWriter { max_per_call: usize::MAX, written: Vec::new(), call_lens: Vec::new() };
+        write_vectored_chunked(&mut w, &bufs, 1024).expect("write succeeds");
+        assert!(w.call_lens.is_empty());
+    }
+}
+
 fn writer(
     args: &'static Args,
-    full_receiver: Sender<Message>,
-    empty_sender: Receiver<Message>,
+    full_receiver: Receiver<Message>,
+    empty_sender: Sender<Message>,
 ) -> Result<()> {
     let stdout_h = std::io::stdout();
-    let so = stdout_h.lock();
-    return Ok(());
+    let mut so = stdout_h.lock();
+    let batch_cap = max(args.high_wm, 1);
+    let mut pending: Vec<Vec<u8>> = Vec::with_capacity(batch_cap);
+    loop {
+        let message: Message = match full_receiver.recv() {
+            StdResult::Ok(m) => m,
+            StdResult::Err(e) => {
+                return Err(e).with_context(||
+                    format!("Bug: Reader thread hung up on writer.")
+                );
+            }
+        };
+        let mut done = false;
+        match message {
+            Message::Line(buf) => pending.push(buf),
+            Message::End(res) => {
+                res.with_context(|| format!("Bug: Recieved error from reader thread."))?;
+                done = true;
+            }
+        }
+        // Coalesce whatever else is already sitting in the channel (up to
+        // the batch cap) so we can flush it all in a single write_vectored.
+        while !done && pending.len() < batch_cap {
+            match full_receiver.try_recv() {
+                StdResult::Ok(Message::Line(buf)) => pending.push(buf),
+                StdResult::Ok(Message::End(res)) => {
+                    res.with_context(|| format!("Bug: Recieved error from reader thread."))?;
+                    done = true;
+                }
+                StdResult::Err(TryRecvError::Empty) => break,
+                StdResult::Err(TryRecvError::Disconnected) => {
+                    return Err(anyhow!("Bug: Reader thread hung up on writer."));
+                }
+            }
+        }
+        if !pending.is_empty() {
+            write_vectored_all(&mut so, &pending).with_context(||
+                format!("Error writing to stdout")
+            )?;
+            for mut buf in pending.drain(..) {
+                buf.clear();
+                // The reader may have already hit EOF (or a head-mode line
+                // limit) and exited, dropping its end of this channel,
+                // before we get around to recycling its buffers. That's not
+                // an error - there's simply no one left who needs them back.
+                // Tail mode also sends freshly-allocated lines that were
+                // never drawn from the pool; once the pool is full again
+                // those are dropped instead of blocking on a reader that is
+                // itself blocked sending us more lines.
+                let _ = empty_sender.try_send(Message::Line(buf));
+            }
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// Raise the soft `RLIMIT_NOFILE` as high as the hard limit allows, so that
+/// opening many `FILE` arguments at once doesn't run us into the default
+/// per-process fd cap. Failure here isn't fatal - we just warn and carry on
+/// with whatever limit we already had.
+#[cfg(unix)]
+fn raise_fd_limit() {
+    let mut lim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut lim) } != 0 {
+        eprintln!(
+            "WARNING: failed to query the file descriptor limit: {}",
+            io::Error::last_os_error()
+        );
+        return;
+    }
+
+    let mut target = lim.rlim_max;
+    #[cfg(target_os = "macos")]
+    {
+        // macOS reports RLIM_INFINITY for rlim_max but setrlimit rejects a
+        // soft limit above OPEN_MAX.
+        if target == libc::RLIM_INFINITY || target > libc::OPEN_MAX as libc::rlim_t {
+            target = libc::OPEN_MAX as libc::rlim_t;
+        }
+    }
+    if target <= lim.rlim_cur {
+        return;
+    }
+
+    lim.rlim_cur = target;
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lim) } != 0 {
+        eprintln!(
+            "WARNING: failed to raise the file descriptor limit: {}",
+            io::Error::last_os_error()
+        );
+    }
 }
 
+#[cfg(not(unix))]
+fn raise_fd_limit() {}
+
 fn amain(args: &'static Args) -> Result<()> {
-    let (full_sender, full_receiver) = bounded::<Message>(2);
-    let (empty_sender, empty_receiver) = bounded::<Message>(2);
-    let buffer = ArrayQueue::<Vec<u8>>::new(args.buffer_lines);
-    
-    return Ok(());
-//     }
-//     let mut max_len = 1024;
-//     let mut read_buf: Vec<u8> = Vec::new();
-//     read_buf.extend(std::iter::repeat(0u8).take(max_len));
-//     eprintln!("Hello, world!");
-//     let mut in_files: VecDeque<InFileish> = VecDeque::new();
-//     if args.files.len() > 0 {
-//         for path_str in args.files {
-//             let path = Path::new(&path_str);
-//             let file = OpenOptions::new().read(true).open(path)
-//                 .with_context(|| format!("Failed to open input file {}", &path_str.to_string_lossy()))
-//                 ?;
-//             in_files.push_back(InFileish::F(file));
-//         }
-//     } else {
-//         in_files.push_back(InFileish::S(io::stdin()));
-//     }
-//     let delimiter: Vec<u8> = unescape_bytes(&args.delimiter.clone().into_vec())
-//         .with_context(|| format!("Failed to parse delimiter"))
-//     ?;
-//     let mut out_file = io::stdout();
-//     let mut in_file = match in_files.pop_front() {
-//         Some(f) => f,
-//         None => return Err(anyhow!("Logic Error: The length was checked right before this, so this should never happen.")),
-//     };
-//     let mut reading = true;
-//     let mut writing = false;
-//     let mut more_to_read = true;
-//     loop {
-//         if buffer.len() >= args.high_wm {
-//             writing = true;
-//         }
-//         if buffer.len() == 0 {
-//             writing = false;
-//         }
-//         if buffer.len() <= args.low_wm {
-//             reading = true;
-//         }
-//         if buffer.len() >= buffer_lines {
-//             reading = false;
-//         }
-//         let will_read = more_to_read && reading;
-//         let will_write = writing;
-//         
-//     }
+    let delimiter: Vec<u8> = unescape_bytes(&args.delimiter.clone().into_vec())
+        .with_context(|| format!("Failed to parse delimiter"))
+    ?;
+
+    raise_fd_limit();
+
+    let mut in_files: VecDeque<InFileish> = VecDeque::new();
+    if args.files.len() > 0 {
+        for path_str in &args.files {
+            let path = Path::new(path_str);
+            let file = OpenOptions::new().read(true).open(path)
+                .with_context(|| format!("Failed to open input file {}", path_str.to_string_lossy()))
+                ?;
+            in_files.push_back(InFileish::F(file));
+        }
+    } else {
+        in_files.push_back(InFileish::S(io::stdin()));
+    }
+
+    let (full_sender, full_receiver) = bounded::<Message>(args.buffer_lines);
+    let (empty_sender, empty_receiver) = bounded::<Message>(args.buffer_lines);
+    for _ in 0..args.buffer_lines {
+        empty_sender.send(Message::Line(Vec::new())).with_context(||
+            format!("Bug: Failed to prime the empty buffer pool.")
+        )?;
+    }
+
+    let reader_handle = thread::spawn(move || {
+        reader(args, full_sender, empty_receiver, in_files, delimiter)
+    });
+    let writer_handle = thread::spawn(move || {
+        writer(args, full_receiver, empty_sender)
+    });
+
+    let reader_result = reader_handle.join()
+        .map_err(|_| anyhow!("Bug: reader thread panicked."))?;
+    let writer_result = writer_handle.join()
+        .map_err(|_| anyhow!("Bug: writer thread panicked."))?;
+
+    reader_result?;
+    writer_result
 }
 
 fn main() -> Result<()> {